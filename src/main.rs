@@ -1,19 +1,33 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use architect_api::{
     cpty::*,
     grpc::{
-        json_service::{cpty_server::CptyServer, orderflow_server::OrderflowServer},
+        health::{HealthCheckRequest, HealthCheckResponse, HealthMetric, HealthStatus},
+        json_service::{
+            cpty_server::CptyServer, health_server::HealthServer,
+            orderflow_server::OrderflowServer,
+        },
         SubscriptionStream,
     },
     orderflow::*,
     AccountId,
 };
+use chrono::Utc;
 use clap::Parser;
 use futures::{pin_mut, select_biased, FutureExt, TryStreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
+    signal::unix::{signal, SignalKind},
     sync::{broadcast, mpsc},
     time::{interval, MissedTickBehavior},
 };
@@ -34,6 +48,71 @@ pub struct VertexService {
     pub cpty_res_tx: broadcast::Sender<CptyResponse>,
     pub orderflow_tx: broadcast::Sender<Orderflow>,
     pub vertex_symbology: symbology::VertexSymbology,
+    /// Terminal statuses we've seen for orders ourselves, remembered for a
+    /// short while until the OMS catches up
+    pub order_status: Mutex<BTreeMap<OrderId, (OrderStatus, Instant)>>,
+    /// When set, new orders are rejected; cancels and account updates continue
+    pub trading_halted: AtomicBool,
+    /// UNIX epoch time of the last successful account poll, or -1 for never
+    pub last_heartbeat: AtomicI64,
+}
+
+/// Account polling runs every 3s, so a few missed polls means we're stale
+const HEARTBEAT_STALE_THRESHOLD_SECS: i64 = 10;
+
+impl VertexService {
+    pub fn new(
+        account_id: AccountId,
+        cpty_req_tx: mpsc::UnboundedSender<CptyRequest>,
+        vertex_symbology: symbology::VertexSymbology,
+    ) -> Self {
+        let (cpty_res_tx, _) = broadcast::channel(100);
+        let (orderflow_tx, _) = broadcast::channel(100);
+        Self {
+            account_id,
+            cpty_req_tx,
+            cpty_res_tx,
+            orderflow_tx,
+            vertex_symbology,
+            order_status: Mutex::new(BTreeMap::new()),
+            trading_halted: AtomicBool::new(false),
+            last_heartbeat: AtomicI64::new(-1),
+        }
+    }
+
+    pub fn cpty_status(&self) -> CptyStatus {
+        let last_heartbeat = self.last_heartbeat.load(Ordering::Relaxed);
+        let connected = last_heartbeat >= 0
+            && Utc::now().timestamp() - last_heartbeat <= HEARTBEAT_STALE_THRESHOLD_SECS;
+        CptyStatus {
+            kind: "VERTEX".to_string(),
+            instance: None,
+            connected,
+            // Vertex has no sessions; every request is signed, so we're as
+            // logged in as we are connected
+            logged_in: connected,
+            last_heartbeat,
+            last_heartbeat_stale_threshold: HEARTBEAT_STALE_THRESHOLD_SECS,
+        }
+    }
+
+    /// Report the trading halt as a health metric; while halted we keep
+    /// serving streams and cancels, but the metric goes out of bounds.
+    pub fn health_check(&self, include_metrics: bool) -> HealthCheckResponse {
+        let metrics = include_metrics.then(|| {
+            let halted = self.trading_halted.load(Ordering::Relaxed);
+            let trading_halted = HealthMetric {
+                timestamp: Utc::now().timestamp() as i32,
+                value: if halted { 1. } else { 0. },
+                should_be_greater_than: None,
+                should_be_less_than: Some(1.),
+                should_be_greater_than_or_equal_to: None,
+                should_be_less_than_or_equal_to: None,
+            };
+            BTreeMap::from_iter([("trading_halted".to_string(), trading_halted)])
+        });
+        HealthCheckResponse { status: HealthStatus::Serving, metrics }
+    }
 }
 
 fn map_broadcast_stream_err(e: BroadcastStreamRecvError) -> Status {
@@ -42,6 +121,17 @@ fn map_broadcast_stream_err(e: BroadcastStreamRecvError) -> Status {
     }
 }
 
+#[tonic::async_trait]
+impl architect_api::grpc::json_service::health_server::Health for VertexService {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let include_metrics = request.into_inner().include_metrics.unwrap_or(false);
+        Ok(Response::new(self.health_check(include_metrics)))
+    }
+}
+
 #[tonic::async_trait]
 impl architect_api::grpc::json_service::cpty_server::Cpty for VertexService {
     type CptyStream = SubscriptionStream<CptyResponse>;
@@ -84,7 +174,7 @@ impl architect_api::grpc::json_service::cpty_server::Cpty for VertexService {
         &self,
         _request: Request<CptyStatusRequest>,
     ) -> Result<Response<CptyStatus>, Status> {
-        Err(Status::unimplemented(""))
+        Ok(Response::new(VertexService::cpty_status(self)))
     }
 
     async fn cptys(
@@ -127,13 +217,26 @@ impl architect_api::grpc::json_service::orderflow_server::Orderflow for VertexSe
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Config {
+    /// Architect account that positions and orders are reported against
     account_id: AccountId,
+    /// Vertex subaccount (sender bytes32 as hex, i.e. address followed by
+    /// subaccount name) that account_id maps to; the signer must match it.
+    /// Required--startup fails if it's missing.
+    #[serde(default)]
+    subaccount: Option<String>,
 }
 
 /// >_ Architect / Vertex
 ///
 /// Set environment variable (via `.env` file or otherwise)
 /// RUST_SDK_PRIVATE_KEY=0x...
+///
+/// The config file is JSON of the form
+/// `{ "account_id": "<uuid>", "subaccount": "0x<64 hex digits>" }`; the
+/// signer's subaccount is checked against `subaccount` at startup.
+///
+/// Send SIGUSR1 to halt trading (new orders are rejected) and SIGUSR2 to resume;
+/// the `trading_halted` health check metric reports the current state.
 #[derive(Debug, Parser)]
 struct Args {
     #[arg(long, short)]
@@ -151,21 +254,15 @@ async fn main() -> Result<()> {
         .with_signer(private_key())
         .await
         .map_err(|e| anyhow!(e))?;
+    verify_signer(&client, &config)?;
     let vertex_symbology = symbology::load_symbology(&client).await?;
 
     let (cpty_req_tx, mut cpty_req_rx) = mpsc::unbounded_channel();
-    let (cpty_res_tx, _) = broadcast::channel(100);
-    let (orderflow_tx, _) = broadcast::channel(100);
-
-    let service = Arc::new(VertexService {
-        account_id: config.account_id,
-        cpty_req_tx,
-        cpty_res_tx,
-        orderflow_tx,
-        vertex_symbology,
-    });
+    let service =
+        Arc::new(VertexService::new(config.account_id, cpty_req_tx, vertex_symbology));
 
     let server_fut = Server::builder()
+        .add_service(HealthServer::from_arc(service.clone()))
         .add_service(CptyServer::from_arc(service.clone()))
         .add_service(OrderflowServer::from_arc(service.clone()))
         .serve("0.0.0.0:50051".parse()?);
@@ -174,11 +271,14 @@ async fn main() -> Result<()> {
     let mut poll_account_interval = interval(Duration::from_secs(3));
     poll_account_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
+    let mut halt_signal = signal(SignalKind::user_defined1())?;
+    let mut resume_signal = signal(SignalKind::user_defined2())?;
+
     loop {
         select_biased! {
             msg = cpty_req_rx.recv().fuse() => {
                 if let Some(msg) = msg {
-                    handle_cpty_request(&service, &client, msg).await?;
+                    handle_cpty_request(&service, &client, &mut cpty_req_rx, msg).await?;
                 }
             }
             res = (&mut server_fut).fuse() => {
@@ -187,25 +287,211 @@ async fn main() -> Result<()> {
             _ = poll_account_interval.tick().fuse() => {
                 service.update_account_summary(&client).await?;
             }
+            _ = halt_signal.recv().fuse() => {
+                warn!("trading halted");
+                service.trading_halted.store(true, Ordering::Relaxed);
+            }
+            _ = resume_signal.recv().fuse() => {
+                warn!("trading resumed");
+                service.trading_halted.store(false, Ordering::Relaxed);
+            }
         }
     }
 }
 
+/// Source of the signer's subaccount, so the startup check can be tested
+trait SignerSubaccount {
+    fn signer_subaccount(&self) -> Result<[u8; 32]>;
+}
+
+impl SignerSubaccount for VertexClient {
+    fn signer_subaccount(&self) -> Result<[u8; 32]> {
+        self.subaccount().map_err(|e| anyhow!(e))
+    }
+}
+
+/// Check that the signer's subaccount is the one configured for account_id,
+/// so a misconfigured key can't trade some other account.
+fn verify_signer(client: &impl SignerSubaccount, config: &Config) -> Result<()> {
+    let subaccount = client.signer_subaccount().context("deriving signer subaccount")?;
+    let address = format!("0x{}", hex::encode(&subaccount[..20]));
+    let subaccount = format!("0x{}", hex::encode(subaccount));
+    info!("signer address: {address}, subaccount: {subaccount}");
+    let expected = match config.subaccount.as_ref() {
+        Some(expected) => expected,
+        None => bail!(
+            "config is missing subaccount for account {}; set it to the signer's subaccount {subaccount} if that's the account to trade",
+            config.account_id
+        ),
+    };
+    let expected_hex = expected.strip_prefix("0x").unwrap_or(expected);
+    if expected_hex.len() != 64 || !expected_hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(
+            "configured subaccount {expected} is invalid; expected 0x followed by 64 hex digits"
+        );
+    }
+    if !subaccount[2..].eq_ignore_ascii_case(expected_hex) {
+        bail!(
+            "signer subaccount {subaccount} does not match configured subaccount {expected} for account {}",
+            config.account_id
+        );
+    }
+    Ok(())
+}
+
 async fn handle_cpty_request(
     service: &VertexService,
     client: &VertexClient,
+    cpty_req_rx: &mut mpsc::UnboundedReceiver<CptyRequest>,
     msg: CptyRequest,
 ) -> Result<()> {
-    debug!("processing cpty request: {:?}", msg);
-    match msg {
-        CptyRequest::Login(_) => {}
-        CptyRequest::Logout(_) => {}
-        CptyRequest::PlaceOrder(order) => {
-            service.place_order(client, order).await?;
-        }
-        CptyRequest::CancelOrder { cancel, original_order } => {
-            service.cancel_order(client, cancel, original_order).await?;
+    let mut next = Some(msg);
+    while let Some(msg) = next.take() {
+        debug!("processing cpty request: {:?}", msg);
+        match msg {
+            CptyRequest::Login(_) => {}
+            CptyRequest::Logout(_) => {}
+            CptyRequest::PlaceOrder(order) => {
+                service.place_order(client, order).await?;
+            }
+            CptyRequest::CancelOrder { cancel, original_order } => {
+                // coalesce any cancels already queued behind this one into a
+                // single Vertex call; anything else is handled after the batch
+                let mut cancels = vec![(cancel, original_order)];
+                while cancels.len() < order_entry::MAX_CANCEL_BATCH
+                    && let Ok(msg) = cpty_req_rx.try_recv()
+                {
+                    match msg {
+                        CptyRequest::CancelOrder { cancel, original_order } => {
+                            cancels.push((cancel, original_order));
+                        }
+                        msg => {
+                            next = Some(msg);
+                            break;
+                        }
+                    }
+                }
+                service.cancel_orders(client, cancels).await?;
+            }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_service() -> VertexService {
+        let (cpty_req_tx, _) = mpsc::unbounded_channel();
+        let vertex_symbology = symbology::VertexSymbology {
+            products: BTreeMap::new(),
+            tradable_products: BTreeMap::new(),
+            execution_info: BTreeMap::new(),
+        };
+        VertexService::new(AccountId::nil(), cpty_req_tx, vertex_symbology)
+    }
+
+    fn test_config(subaccount: Option<&str>) -> Config {
+        Config {
+            account_id: AccountId::nil(),
+            subaccount: subaccount.map(|s| s.to_string()),
+        }
+    }
+
+    struct MockSigner(Option<[u8; 32]>);
+
+    impl SignerSubaccount for MockSigner {
+        fn signer_subaccount(&self) -> Result<[u8; 32]> {
+            self.0.ok_or_else(|| anyhow!("no signer"))
+        }
+    }
+
+    fn test_subaccount() -> [u8; 32] {
+        let mut subaccount = [0u8; 32];
+        subaccount[..20].copy_from_slice(&[0xab; 20]);
+        subaccount[20..27].copy_from_slice(b"default");
+        subaccount
+    }
+
+    #[test]
+    fn verify_signer_matches_config() {
+        let client = MockSigner(Some(test_subaccount()));
+        let subaccount = hex::encode(test_subaccount());
+        verify_signer(&client, &test_config(Some(&format!("0x{subaccount}")))).unwrap();
+        // case and the 0x prefix don't matter
+        let upper = subaccount.to_uppercase();
+        verify_signer(&client, &test_config(Some(&format!("0x{upper}")))).unwrap();
+        verify_signer(&client, &test_config(Some(&subaccount))).unwrap();
+    }
+
+    #[test]
+    fn verify_signer_rejects_mismatch() {
+        let mut other = test_subaccount();
+        other[0] = 0xcd;
+        let client = MockSigner(Some(other));
+        let config = test_config(Some(&format!("0x{}", hex::encode(test_subaccount()))));
+        let err = verify_signer(&client, &config).unwrap_err();
+        assert!(err.to_string().contains("does not match configured subaccount"));
+    }
+
+    #[test]
+    fn verify_signer_rejects_malformed_subaccount() {
+        let client = MockSigner(Some(test_subaccount()));
+        let config = test_config(Some("0xabcd"));
+        let err = verify_signer(&client, &config).unwrap_err();
+        assert!(err.to_string().contains("expected 0x followed by 64 hex digits"));
+    }
+
+    #[test]
+    fn verify_signer_requires_subaccount() {
+        let client = MockSigner(Some(test_subaccount()));
+        let config: Config = serde_json::from_str(
+            r#"{ "account_id": "00000000-0000-0000-0000-000000000000" }"#,
+        )
+        .unwrap();
+        let err = verify_signer(&client, &config).unwrap_err();
+        assert!(err.to_string().contains("config is missing subaccount"));
+    }
+
+    #[test]
+    fn verify_signer_fails_without_signer() {
+        let client = MockSigner(None);
+        let config = test_config(Some(&format!("0x{}", hex::encode(test_subaccount()))));
+        let err = verify_signer(&client, &config).unwrap_err();
+        assert!(err.to_string().contains("deriving signer subaccount"));
+    }
+
+    #[test]
+    fn health_check_reports_trading_halt() {
+        let service = test_service();
+        let trading_halted = |service: &VertexService| {
+            let res = service.health_check(true);
+            assert!(matches!(res.status, HealthStatus::Serving));
+            res.metrics.unwrap()["trading_halted"].value
+        };
+        assert_eq!(trading_halted(&service), 0.);
+        service.trading_halted.store(true, Ordering::Relaxed);
+        assert_eq!(trading_halted(&service), 1.);
+        service.trading_halted.store(false, Ordering::Relaxed);
+        assert_eq!(trading_halted(&service), 0.);
+    }
+
+    #[test]
+    fn cpty_status_unaffected_by_trading_halt() {
+        let service = test_service();
+        service.last_heartbeat.store(Utc::now().timestamp(), Ordering::Relaxed);
+        service.trading_halted.store(true, Ordering::Relaxed);
+        let status = service.cpty_status();
+        assert!(status.connected && status.logged_in);
+        assert_eq!(status.instance, None);
+    }
+
+    #[test]
+    fn cpty_status_stale_without_heartbeat() {
+        let service = test_service();
+        let status = service.cpty_status();
+        assert!(!status.connected && !status.logged_in);
+        assert_eq!(status.last_heartbeat, -1);
+    }
+}
@@ -1,11 +1,118 @@
 use super::VertexService;
 use anyhow::{anyhow, bail, Result};
 use architect_api::orderflow::*;
+use log::warn;
 use rust_decimal::prelude::*;
-use vertex_sdk::{prelude::*, vertex_utils::math::f64_to_x18};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use vertex_sdk::{
+    engine::{ExecuteResponse, Status},
+    prelude::*,
+    vertex_utils::math::f64_to_x18,
+};
+
+/// How long to remember terminal order statuses we've seen ourselves
+const ORDER_STATUS_TTL: Duration = Duration::from_secs(300);
+
+/// Most digests to send to Vertex in a single cancellation
+pub const MAX_CANCEL_BATCH: usize = 20;
+
+/// Outcome of a cancellation that Vertex received and answered
+pub enum CancelResponse {
+    /// Digests that were canceled
+    Canceled(Vec<[u8; 32]>),
+    /// Vertex refused the cancellation outright; nothing was canceled
+    Rejected(String),
+}
+
+/// The Vertex calls made by order entry, so they can be stubbed out in tests
+#[allow(async_fn_in_trait)] // only driven from the main loop, never spawned
+pub trait OrderEntryClient {
+    /// Place a limit order, returning its digest if Vertex accepted it
+    async fn place_order(
+        &self,
+        product_id: u32,
+        amount: i128,
+        price_x18: i128,
+    ) -> Result<Option<[u8; 32]>>;
+
+    /// Cancel the given digests; errors mean we don't know whether Vertex
+    /// acted on the request (e.g. timeouts)
+    async fn cancel_orders(
+        &self,
+        digests: Vec<[u8; 32]>,
+        product_ids: Vec<u32>,
+    ) -> Result<CancelResponse>;
+}
+
+impl OrderEntryClient for VertexClient {
+    async fn place_order(
+        &self,
+        product_id: u32,
+        amount: i128,
+        price_x18: i128,
+    ) -> Result<Option<[u8; 32]>> {
+        let res = self
+            .place_order_builder()
+            .product_id(product_id)
+            .amount(amount)
+            .price_x18(price_x18)
+            .execute()
+            .await
+            .map_err(|e| anyhow!(e))?;
+        Ok(res.map(|res| res.digest))
+    }
+
+    async fn cancel_orders(
+        &self,
+        digests: Vec<[u8; 32]>,
+        product_ids: Vec<u32>,
+    ) -> Result<CancelResponse> {
+        match self
+            .cancellation_builder()
+            .digests(digests)
+            .product_ids(product_ids)
+            .execute()
+            .await
+        {
+            Ok(Some(res)) => Ok(CancelResponse::Canceled(
+                res.cancelled_orders.into_iter().map(|co| co.digest).collect(),
+            )),
+            Ok(None) => bail!("no response from vertex"),
+            Err(e) => match vertex_rejection(&e.to_string()) {
+                Some(reason) => Ok(CancelResponse::Rejected(reason)),
+                None => Err(anyhow!(e)),
+            },
+        }
+    }
+}
+
+/// The sdk reports failure responses from Vertex as the serialized response;
+/// anything else (transport errors, timeouts) isn't a rejection.
+fn vertex_rejection(message: &str) -> Option<String> {
+    let res = serde_json::from_str::<ExecuteResponse>(message).ok()?;
+    if res.status != Status::Failure {
+        return None;
+    }
+    Some(res.error.unwrap_or_else(|| "rejected by vertex".to_string()))
+}
 
 impl VertexService {
-    pub async fn place_order(&self, client: &VertexClient, order: Order) -> Result<()> {
+    pub async fn place_order(
+        &self,
+        client: &impl OrderEntryClient,
+        order: Order,
+    ) -> Result<()> {
+        if self.trading_halted.load(Ordering::Relaxed) {
+            let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
+                order_id: order.id,
+                reason: OrderRejectReason::Unknown,
+                message: Some("trading halted".to_string()),
+            }));
+            return Ok(());
+        }
         let info = match self
             .vertex_symbology
             .execution_info
@@ -17,7 +124,7 @@ impl VertexService {
                 let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
                     order_id: order.id,
                     reason: OrderRejectReason::Unknown,
-                    message: Some(format!("no execution info for symbol")),
+                    message: Some("no execution info for symbol".to_string()),
                 }));
                 return Ok(());
             }
@@ -34,7 +141,7 @@ impl VertexService {
                 let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
                     order_id: order.id,
                     reason: OrderRejectReason::Unknown,
-                    message: Some(format!("unable to cast quantity")),
+                    message: Some("unable to cast quantity".to_string()),
                 }));
                 return Ok(());
             }
@@ -45,7 +152,7 @@ impl VertexService {
                     let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
                         order_id: order.id,
                         reason: OrderRejectReason::UnsupportedOrderType,
-                        message: Some(format!("unsupported post-only flag")),
+                        message: Some("unsupported post-only flag".to_string()),
                     }));
                     return Ok(());
                 }
@@ -55,7 +162,7 @@ impl VertexService {
                 let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
                     order_id: order.id,
                     reason: OrderRejectReason::UnsupportedOrderType,
-                    message: Some(format!("unsupported order type")),
+                    message: Some("unsupported order type".to_string()),
                 }));
                 return Ok(());
             }
@@ -66,26 +173,21 @@ impl VertexService {
                 let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
                     order_id: order.id,
                     reason: OrderRejectReason::Unknown,
-                    message: Some(format!("unable to cast price")),
+                    message: Some("unable to cast price".to_string()),
                 }));
                 return Ok(());
             }
         };
-        let res = match client
-            .place_order_builder()
-            .product_id(product_id)
-            .amount(f64_to_x18(quantity_f64))
-            .price_x18(f64_to_x18(price_f64))
-            .execute()
+        let digest = match client
+            .place_order(product_id, f64_to_x18(quantity_f64), f64_to_x18(price_f64))
             .await
-            .map_err(|e| anyhow!(e))
         {
-            Ok(Some(res)) => res,
+            Ok(Some(digest)) => digest,
             Ok(None) => {
                 let _ = self.orderflow_tx.send(Orderflow::OrderReject(OrderReject {
                     order_id: order.id,
                     reason: OrderRejectReason::Unknown,
-                    message: Some(format!("unable to place order")),
+                    message: Some("unable to place order".to_string()),
                 }));
                 return Ok(());
             }
@@ -98,7 +200,7 @@ impl VertexService {
                 return Ok(());
             }
         };
-        let exchange_order_id = format!("0x{}", hex::encode(res.digest));
+        let exchange_order_id = format!("0x{}", hex::encode(digest));
         let _ = self.orderflow_tx.send(Orderflow::OrderAck(OrderAck {
             order_id: order.id,
             exchange_order_id: Some(exchange_order_id),
@@ -106,20 +208,101 @@ impl VertexService {
         Ok(())
     }
 
-    pub async fn cancel_order(
+    /// Cancel a batch of orders in a single Vertex call, reporting the outcome
+    /// of each cancel individually.
+    pub async fn cancel_orders(
         &self,
-        client: &VertexClient,
-        cancel: Cancel,
-        original_order: Option<Order>,
+        client: &impl OrderEntryClient,
+        cancels: Vec<(Cancel, Option<Order>)>,
     ) -> Result<()> {
+        let mut pending: Vec<(Cancel, OrderId, [u8; 32], u32)> = vec![];
+        for (cancel, original_order) in cancels {
+            if let Some((order_id, digest, product_id)) =
+                self.prepare_cancel(&cancel, original_order)
+            {
+                if pending.iter().any(|(_, _, d, _)| *d == digest) {
+                    self.reject_cancel(&cancel, "duplicate cancel for order");
+                    continue;
+                }
+                pending.push((cancel, order_id, digest, product_id));
+            }
+        }
+        for batch in pending.chunks(MAX_CANCEL_BATCH) {
+            self.cancel_batch(client, batch).await;
+        }
+        Ok(())
+    }
+
+    async fn cancel_batch(
+        &self,
+        client: &impl OrderEntryClient,
+        batch: &[(Cancel, OrderId, [u8; 32], u32)],
+    ) {
+        let res = client
+            .cancel_orders(
+                batch.iter().map(|(_, _, digest, _)| *digest).collect(),
+                batch.iter().map(|(_, _, _, product_id)| *product_id).collect(),
+            )
+            .await;
+        let outcomes: Vec<Result<bool, String>> = match res {
+            Ok(CancelResponse::Canceled(cancelled_digests)) => batch
+                .iter()
+                .map(|(_, _, digest, _)| Ok(cancelled_digests.contains(digest)))
+                .collect(),
+            Ok(CancelResponse::Rejected(reason)) if batch.len() > 1 => {
+                // nothing was canceled, so it's safe to retry; don't let one
+                // bad digest sink the rest of the batch
+                warn!("batched cancel rejected, retrying individually: {reason}");
+                let mut outcomes = vec![];
+                for (_, _, digest, product_id) in batch {
+                    let res =
+                        client.cancel_orders(vec![*digest], vec![*product_id]).await;
+                    outcomes.push(match res {
+                        Ok(CancelResponse::Canceled(cancelled_digests)) => {
+                            Ok(cancelled_digests.contains(digest))
+                        }
+                        Ok(CancelResponse::Rejected(reason)) => Err(reason),
+                        Err(e) => Err(e.to_string()),
+                    });
+                }
+                outcomes
+            }
+            Ok(CancelResponse::Rejected(reason)) => vec![Err(reason)],
+            // the batch may or may not have gone through; retrying would only
+            // stall the main loop, so report the error and let the OMS reconcile
+            Err(e) => batch.iter().map(|_| Err(e.to_string())).collect(),
+        };
+        for ((cancel, order_id, _, _), outcome) in batch.iter().zip(outcomes) {
+            match outcome {
+                Ok(true) => {
+                    self.set_order_status(*order_id, OrderStatus::Canceled);
+                    let _ =
+                        self.orderflow_tx.send(Orderflow::OrderCanceled(OrderCanceled {
+                            order_id: *order_id,
+                            cancel_id: Some(cancel.cancel_id),
+                        }));
+                }
+                Ok(false) => {
+                    self.reject_cancel(cancel, "order didn't cancel");
+                }
+                Err(e) => {
+                    self.reject_cancel(cancel, &format!("unable to cancel order: {e}"));
+                }
+            }
+        }
+    }
+
+    /// Resolve the digest and product id to cancel, or reject the cancel if
+    /// there's nothing to send to Vertex.
+    fn prepare_cancel(
+        &self,
+        cancel: &Cancel,
+        original_order: Option<Order>,
+    ) -> Option<(OrderId, [u8; 32], u32)> {
         macro_rules! reject {
             ($message:expr) => {
-                let _ = self.orderflow_tx.send(Orderflow::CancelReject(CancelReject {
-                    cancel_id: cancel.cancel_id,
-                    order_id: cancel.order_id,
-                    message: Some(format!($message)),
-                }));
-                return Ok(());
+                self.reject_cancel(cancel, $message);
+                return None;
             };
         }
         let original_order = match original_order {
@@ -128,8 +311,45 @@ impl VertexService {
                 reject!("no original order");
             }
         };
+        // a terminal status from either our own records or the OMS is final
+        let status = match self.order_status(&original_order.id) {
+            Some(status) if status.is_dead() => status,
+            _ => original_order.status,
+        };
+        match status {
+            OrderStatus::Out => {
+                reject!("order already out");
+            }
+            OrderStatus::Canceled => {
+                reject!("order already canceled");
+            }
+            OrderStatus::ReconciledOut => {
+                reject!("order already reconciled out");
+            }
+            OrderStatus::Rejected => {
+                reject!("order already rejected");
+            }
+            OrderStatus::Pending
+            | OrderStatus::Open
+            | OrderStatus::Canceling
+            | OrderStatus::Stale
+            | OrderStatus::Unknown => {}
+        }
+        let product_id: u32 = match self
+            .vertex_symbology
+            .execution_info
+            .get(&original_order.symbol)
+            .and_then(|i| i.get(&original_order.execution_venue))
+            .and_then(|info| info.exchange_symbol.as_ref())
+            .and_then(|product_id_str| product_id_str.parse().ok())
+        {
+            Some(product_id) => product_id,
+            None => {
+                reject!("no product id for symbol");
+            }
+        };
         let digest_s = match original_order.exchange_order_id.as_ref() {
-            Some(xoid) => match xoid.strip_suffix("0x") {
+            Some(xoid) => match xoid.strip_prefix("0x") {
                 Some(digest_s) => digest_s,
                 None => {
                     reject!("invalid exchange order id");
@@ -140,31 +360,424 @@ impl VertexService {
             }
         };
         let mut digest = [0u8; 32];
-        if let Err(_) = hex::decode_to_slice(digest_s, &mut digest) {
+        if hex::decode_to_slice(digest_s, &mut digest).is_err() {
             reject!("invalid exchange order id");
         }
-        let res = match client
-            .cancellation_builder()
-            .digests(vec![digest])
-            .product_ids(vec![])
-            .execute()
-            .await
-            .map_err(|e| anyhow!(e))
-        {
-            Ok(Some(res)) => res,
-            Ok(None) | Err(_) => {
-                reject!("unable to cancel order");
+        Some((original_order.id, digest, product_id))
+    }
+
+    fn reject_cancel(&self, cancel: &Cancel, message: &str) {
+        let _ = self.orderflow_tx.send(Orderflow::CancelReject(CancelReject {
+            cancel_id: cancel.cancel_id,
+            order_id: cancel.order_id,
+            message: Some(message.to_string()),
+        }));
+    }
+
+    fn order_status(&self, order_id: &OrderId) -> Option<OrderStatus> {
+        let order_status = self.order_status.lock().unwrap();
+        match order_status.get(order_id) {
+            Some((status, at)) if at.elapsed() < ORDER_STATUS_TTL => Some(*status),
+            _ => None,
+        }
+    }
+
+    fn set_order_status(&self, order_id: OrderId, status: OrderStatus) {
+        let now = Instant::now();
+        let mut order_status = self.order_status.lock().unwrap();
+        order_status.retain(|_, (_, at)| now.duration_since(*at) < ORDER_STATUS_TTL);
+        order_status.insert(order_id, (status, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbology::VertexSymbology;
+    use architect_api::{symbology::*, AccountId, Dir, UserId};
+    use rust_decimal_macros::dec;
+    use std::{collections::BTreeMap, sync::Mutex};
+    use tokio::sync::{broadcast, mpsc};
+    use uuid::Uuid;
+
+    const PRODUCT_ID: u32 = 2;
+
+    /// Digests and product ids passed to a single cancellation call
+    type CancelCall = (Vec<[u8; 32]>, Vec<u32>);
+
+    #[derive(Default)]
+    struct MockClient {
+        /// Product ids passed to each place call
+        place_calls: Mutex<Vec<u32>>,
+        cancel_calls: Mutex<Vec<CancelCall>>,
+        /// Vertex rejects any cancellation including this digest
+        bad_digest: Option<[u8; 32]>,
+        /// Every cancellation fails without reaching Vertex
+        transport_error: bool,
+    }
+
+    impl OrderEntryClient for MockClient {
+        async fn place_order(
+            &self,
+            product_id: u32,
+            _amount: i128,
+            _price_x18: i128,
+        ) -> Result<Option<[u8; 32]>> {
+            let mut place_calls = self.place_calls.lock().unwrap();
+            place_calls.push(product_id);
+            Ok(Some(digest(place_calls.len() as u64)))
+        }
+
+        async fn cancel_orders(
+            &self,
+            digests: Vec<[u8; 32]>,
+            product_ids: Vec<u32>,
+        ) -> Result<CancelResponse> {
+            self.cancel_calls.lock().unwrap().push((digests.clone(), product_ids));
+            if self.transport_error {
+                bail!("timed out");
             }
+            if self.bad_digest.is_some_and(|bad_digest| digests.contains(&bad_digest)) {
+                return Ok(CancelResponse::Rejected("order not found".to_string()));
+            }
+            Ok(CancelResponse::Canceled(digests))
+        }
+    }
+
+    fn symbol() -> TradableProduct {
+        let base = Product::perpetual("BTC-USDC", Some("VERTEX")).unwrap();
+        TradableProduct::new(&base, Some(&Product::crypto("USDC").unwrap())).unwrap()
+    }
+
+    fn test_service() -> (VertexService, broadcast::Receiver<Orderflow>) {
+        let venue: ExecutionVenue = "VERTEX".into();
+        let info = ExecutionInfo {
+            execution_venue: venue.clone(),
+            exchange_symbol: Some(PRODUCT_ID.to_string()),
+            tick_size: TickSize::Simple(dec!(1)),
+            step_size: dec!(0.001),
+            min_order_quantity: dec!(0.001),
+            min_order_quantity_unit: MinOrderQuantityUnit::Base,
+            is_delisted: false,
+            initial_margin: None,
+            maintenance_margin: None,
         };
-        for co in res.cancelled_orders {
-            if co.digest == digest {
-                let _ = self.orderflow_tx.send(Orderflow::OrderCanceled(OrderCanceled {
-                    order_id: original_order.id,
-                    cancel_id: Some(cancel.cancel_id),
-                }));
-                return Ok(());
+        let vertex_symbology = VertexSymbology {
+            products: BTreeMap::new(),
+            tradable_products: BTreeMap::from_iter([(PRODUCT_ID, symbol())]),
+            execution_info: BTreeMap::from_iter([(
+                symbol(),
+                BTreeMap::from_iter([(venue, info)]),
+            )]),
+        };
+        let (cpty_req_tx, _) = mpsc::unbounded_channel();
+        let service = VertexService::new(AccountId::nil(), cpty_req_tx, vertex_symbology);
+        let orderflow_rx = service.orderflow_tx.subscribe();
+        (service, orderflow_rx)
+    }
+
+    fn digest(seqno: u64) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        digest[..8].copy_from_slice(&seqno.to_be_bytes());
+        digest
+    }
+
+    fn test_order(seqno: u64, status: OrderStatus) -> Order {
+        Order {
+            id: OrderId::nil(seqno),
+            parent_id: None,
+            exchange_order_id: Some(format!("0x{}", hex::encode(digest(seqno)))),
+            recv_time: 0,
+            recv_time_ns: 0,
+            status,
+            reject_reason: None,
+            reject_message: None,
+            symbol: symbol(),
+            trader: UserId::anonymous(),
+            account: AccountId::nil(),
+            dir: Dir::Buy,
+            quantity: dec!(1),
+            filled_quantity: dec!(0),
+            average_fill_price: None,
+            order_type: OrderType::Limit(LimitOrderType {
+                limit_price: dec!(100),
+                post_only: false,
+            }),
+            time_in_force: TimeInForce::GoodTilCancel,
+            source: OrderSource::API,
+            execution_venue: "VERTEX".into(),
+            is_short_sale: None,
+        }
+    }
+
+    fn test_cancel(order: &Order) -> Cancel {
+        Cancel {
+            cancel_id: Uuid::new_v4(),
+            order_id: order.id,
+            recv_time: 0,
+            recv_time_ns: 0,
+            status: CancelStatus::Pending,
+            reject_reason: None,
+        }
+    }
+
+    fn expect_cancel_reject(
+        orderflow_rx: &mut broadcast::Receiver<Orderflow>,
+        cancel: &Cancel,
+        message: &str,
+    ) {
+        match orderflow_rx.try_recv() {
+            Ok(Orderflow::CancelReject(reject)) => {
+                assert_eq!(reject.cancel_id, cancel.cancel_id);
+                assert_eq!(reject.order_id, cancel.order_id);
+                assert_eq!(reject.message.as_deref(), Some(message));
+            }
+            other => panic!("expected cancel reject, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_out_order_is_rejected_locally() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let order = test_order(1, OrderStatus::Out);
+        let cancel = test_cancel(&order);
+        service
+            .cancel_orders(&client, vec![(cancel.clone(), Some(order))])
+            .await
+            .unwrap();
+        expect_cancel_reject(&mut orderflow_rx, &cancel, "order already out");
+        assert!(client.cancel_calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_canceled_order_is_rejected_locally() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        // the OMS hasn't caught up yet, but we've already seen the cancel
+        let order = test_order(1, OrderStatus::Open);
+        service.set_order_status(order.id, OrderStatus::Canceled);
+        let cancel = test_cancel(&order);
+        service
+            .cancel_orders(&client, vec![(cancel.clone(), Some(order))])
+            .await
+            .unwrap();
+        expect_cancel_reject(&mut orderflow_rx, &cancel, "order already canceled");
+        assert!(client.cancel_calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_live_order_goes_to_vertex() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let order = test_order(1, OrderStatus::Open);
+        let cancel = test_cancel(&order);
+        service
+            .cancel_orders(&client, vec![(cancel.clone(), Some(order.clone()))])
+            .await
+            .unwrap();
+        match orderflow_rx.try_recv() {
+            Ok(Orderflow::OrderCanceled(canceled)) => {
+                assert_eq!(canceled.order_id, order.id);
+                assert_eq!(canceled.cancel_id, Some(cancel.cancel_id));
+            }
+            other => panic!("expected order canceled, got {other:?}"),
+        }
+        assert_eq!(
+            *client.cancel_calls.lock().unwrap(),
+            vec![(vec![digest(1)], vec![PRODUCT_ID])]
+        );
+        assert_eq!(service.order_status(&order.id), Some(OrderStatus::Canceled));
+    }
+
+    fn expect_order_canceled(
+        orderflow_rx: &mut broadcast::Receiver<Orderflow>,
+        cancel: &Cancel,
+    ) {
+        match orderflow_rx.try_recv() {
+            Ok(Orderflow::OrderCanceled(canceled)) => {
+                assert_eq!(canceled.order_id, cancel.order_id);
+                assert_eq!(canceled.cancel_id, Some(cancel.cancel_id));
             }
+            other => panic!("expected order canceled, got {other:?}"),
         }
-        reject!("order didn't cancel");
+    }
+
+    #[tokio::test]
+    async fn cancel_three_orders_in_one_call() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let orders: Vec<Order> =
+            (1..=3).map(|seqno| test_order(seqno, OrderStatus::Open)).collect();
+        let cancels: Vec<Cancel> = orders.iter().map(test_cancel).collect();
+        service
+            .cancel_orders(
+                &client,
+                cancels.iter().cloned().zip(orders.into_iter().map(Some)).collect(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            *client.cancel_calls.lock().unwrap(),
+            vec![(vec![digest(1), digest(2), digest(3)], vec![PRODUCT_ID; 3])]
+        );
+        for cancel in &cancels {
+            expect_order_canceled(&mut orderflow_rx, cancel);
+        }
+        assert!(orderflow_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rejected_batch_falls_back_to_individual_cancels() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient { bad_digest: Some(digest(2)), ..Default::default() };
+        let orders: Vec<Order> =
+            (1..=3).map(|seqno| test_order(seqno, OrderStatus::Open)).collect();
+        let cancels: Vec<Cancel> = orders.iter().map(test_cancel).collect();
+        service
+            .cancel_orders(
+                &client,
+                cancels.iter().cloned().zip(orders.into_iter().map(Some)).collect(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.cancel_calls.lock().unwrap().len(), 4);
+        expect_order_canceled(&mut orderflow_rx, &cancels[0]);
+        expect_cancel_reject(
+            &mut orderflow_rx,
+            &cancels[1],
+            "unable to cancel order: order not found",
+        );
+        expect_order_canceled(&mut orderflow_rx, &cancels[2]);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_not_retried() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient { transport_error: true, ..Default::default() };
+        let orders: Vec<Order> =
+            (1..=3).map(|seqno| test_order(seqno, OrderStatus::Open)).collect();
+        let cancels: Vec<Cancel> = orders.iter().map(test_cancel).collect();
+        service
+            .cancel_orders(
+                &client,
+                cancels.iter().cloned().zip(orders.into_iter().map(Some)).collect(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.cancel_calls.lock().unwrap().len(), 1);
+        for cancel in &cancels {
+            expect_cancel_reject(
+                &mut orderflow_rx,
+                cancel,
+                "unable to cancel order: timed out",
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn large_batches_are_split() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let n = MAX_CANCEL_BATCH as u64 + 1;
+        let orders: Vec<Order> =
+            (1..=n).map(|seqno| test_order(seqno, OrderStatus::Open)).collect();
+        let cancels: Vec<Cancel> = orders.iter().map(test_cancel).collect();
+        service
+            .cancel_orders(
+                &client,
+                cancels.iter().cloned().zip(orders.into_iter().map(Some)).collect(),
+            )
+            .await
+            .unwrap();
+        let batch_sizes: Vec<usize> = client
+            .cancel_calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(digests, _)| digests.len())
+            .collect();
+        assert_eq!(batch_sizes, vec![MAX_CANCEL_BATCH, 1]);
+        for cancel in &cancels {
+            expect_order_canceled(&mut orderflow_rx, cancel);
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_cancel_in_batch_is_rejected() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let order = test_order(1, OrderStatus::Open);
+        let first = test_cancel(&order);
+        let second = test_cancel(&order);
+        service
+            .cancel_orders(
+                &client,
+                vec![(first.clone(), Some(order.clone())), (second.clone(), Some(order))],
+            )
+            .await
+            .unwrap();
+        expect_cancel_reject(&mut orderflow_rx, &second, "duplicate cancel for order");
+        expect_order_canceled(&mut orderflow_rx, &first);
+        assert_eq!(
+            *client.cancel_calls.lock().unwrap(),
+            vec![(vec![digest(1)], vec![PRODUCT_ID])]
+        );
+    }
+
+    #[tokio::test]
+    async fn place_order_rejected_while_halted() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let order = test_order(1, OrderStatus::Pending);
+        service.trading_halted.store(true, Ordering::Relaxed);
+        service.place_order(&client, order.clone()).await.unwrap();
+        match orderflow_rx.try_recv() {
+            Ok(Orderflow::OrderReject(reject)) => {
+                assert_eq!(reject.order_id, order.id);
+                assert_eq!(reject.message.as_deref(), Some("trading halted"));
+            }
+            other => panic!("expected order reject, got {other:?}"),
+        }
+        assert!(client.place_calls.lock().unwrap().is_empty());
+        // clearing the halt lets orders through again
+        service.trading_halted.store(false, Ordering::Relaxed);
+        service.place_order(&client, order.clone()).await.unwrap();
+        match orderflow_rx.try_recv() {
+            Ok(Orderflow::OrderAck(ack)) => {
+                assert_eq!(ack.order_id, order.id);
+                assert_eq!(
+                    ack.exchange_order_id,
+                    Some(format!("0x{}", hex::encode(digest(1))))
+                );
+            }
+            other => panic!("expected order ack, got {other:?}"),
+        }
+        assert_eq!(*client.place_calls.lock().unwrap(), vec![PRODUCT_ID]);
+    }
+
+    #[tokio::test]
+    async fn cancel_order_while_halted() {
+        let (service, mut orderflow_rx) = test_service();
+        let client = MockClient::default();
+        let order = test_order(1, OrderStatus::Open);
+        let cancel = test_cancel(&order);
+        service.trading_halted.store(true, Ordering::Relaxed);
+        service
+            .cancel_orders(&client, vec![(cancel.clone(), Some(order))])
+            .await
+            .unwrap();
+        expect_order_canceled(&mut orderflow_rx, &cancel);
+    }
+
+    #[test]
+    fn vertex_rejection_only_for_failure_responses() {
+        let failure = r#"{
+  "status": "failure",
+  "error": "order not found",
+  "error_code": 2020
+}"#;
+        assert_eq!(vertex_rejection(failure).as_deref(), Some("order not found"));
+        assert_eq!(vertex_rejection("error sending request: timed out"), None);
     }
 }
@@ -5,7 +5,7 @@ use chrono::Utc;
 use log::{debug, error, warn};
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::atomic::Ordering};
 use vertex_sdk::prelude::*;
 
 impl VertexService {
@@ -77,6 +77,7 @@ impl VertexService {
             statistics: None,
             is_snapshot: true,
         });
+        self.last_heartbeat.store(now.timestamp(), Ordering::Relaxed);
         Ok(())
     }
 }
@@ -26,7 +26,7 @@ pub async fn load_symbology(client: &VertexClient) -> Result<VertexSymbology> {
             Some("spot") | None => {
                 products.insert(asset.product_id, Product::crypto(&asset.symbol)?);
             }
-            Some("perp") | _ => {
+            _ => {
                 // NB: these are considered later
             }
         }